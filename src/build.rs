@@ -0,0 +1,52 @@
+//! Build directories: persistent directories inside a [`Workspace`](crate::workspace::Workspace)
+//! used to run commands against a fetched source tree.
+
+use crate::cmd::Command;
+use crate::workspace::{Deadline, Workspace};
+use std::ffi::OsString;
+
+/// A named, persistent directory inside a [`Workspace`](crate::workspace::Workspace).
+///
+/// Use [`Workspace::build_dir`](crate::workspace::Workspace::build_dir) to create one.
+pub struct BuildDirectory {
+    workspace: Workspace,
+    name: String,
+    deadline: Option<Deadline>,
+}
+
+impl BuildDirectory {
+    pub(crate) fn new(workspace: Workspace, name: &str) -> Self {
+        BuildDirectory {
+            workspace,
+            name: name.into(),
+            deadline: None,
+        }
+    }
+
+    /// Attach an overall [`Deadline`](crate::workspace::Deadline) to this build directory.
+    ///
+    /// Every [`Command`] created afterwards with [`cmd`](BuildDirectory::cmd) automatically
+    /// inherits it, so a build closure that runs several commands (fetch, build, test, doc, ...)
+    /// doesn't need to attach the same deadline by hand to each one.
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Prepare a [`Command`] to run inside this build directory's workspace, automatically
+    /// carrying over the deadline set with [`with_deadline`](BuildDirectory::with_deadline), if
+    /// any. Build closures should prefer this over [`Command::new`] so the deadline isn't
+    /// silently dropped.
+    pub fn cmd(&self, program: impl Into<OsString>) -> Command<'_> {
+        let command = Command::new(&self.workspace, program);
+        match self.deadline {
+            Some(deadline) => command.deadline(deadline),
+            None => command,
+        }
+    }
+
+    /// The name this build directory was created with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
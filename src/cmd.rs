@@ -0,0 +1,212 @@
+//! Spawning and supervising commands inside a [`Workspace`](crate::workspace::Workspace).
+
+use crate::workspace::{Deadline, RegistryProtocol, Workspace};
+use failure::{err_msg, Error, ResultExt};
+use log::info;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command as StdCommand, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use wait_timeout::ChildExt;
+
+/// The Docker image used to run commands inside an isolated sandbox.
+#[derive(Debug, Clone)]
+pub struct SandboxImage {
+    name: String,
+}
+
+impl SandboxImage {
+    /// Use a remote image, identified by `name`, pulling it the next time it's needed.
+    pub fn remote(name: &str) -> Result<Self, Error> {
+        Ok(SandboxImage { name: name.into() })
+    }
+}
+
+/// A command to run inside a [`Workspace`](crate::workspace::Workspace).
+///
+/// The command's own timeout (set with [`timeout`](Command::timeout) or inherited from
+/// [`WorkspaceBuilder::command_timeout`](crate::workspace::WorkspaceBuilder::command_timeout))
+/// resets every time it's started. Attaching a [`Deadline`] with [`Command::deadline`] bounds it
+/// further: the command is given whichever is smaller of its own timeout and the time left until
+/// the deadline, and it fails immediately without starting if the deadline has already passed.
+pub struct Command<'ws> {
+    workspace: &'ws Workspace,
+    program: OsString,
+    args: Vec<OsString>,
+    envs: HashMap<OsString, OsString>,
+    cd: Option<PathBuf>,
+    timeout: Option<Duration>,
+    no_output_timeout: Option<Duration>,
+    deadline: Option<Deadline>,
+}
+
+impl<'ws> Command<'ws> {
+    /// Prepare `program` to be run inside `workspace`.
+    pub fn new(workspace: &'ws Workspace, program: impl Into<OsString>) -> Self {
+        Command {
+            workspace,
+            program: program.into(),
+            args: Vec::new(),
+            envs: HashMap::new(),
+            cd: None,
+            timeout: workspace.default_command_timeout(),
+            no_output_timeout: workspace.default_command_no_output_timeout(),
+            deadline: None,
+        }
+    }
+
+    /// Append arguments to the command line.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set an environment variable for the command.
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.insert(key.into(), value.into());
+        self
+    }
+
+    /// Run the command inside `dir` instead of the current directory.
+    pub fn cd(mut self, dir: impl AsRef<Path>) -> Self {
+        self.cd = Some(dir.as_ref().into());
+        self
+    }
+
+    /// Override the workspace-wide
+    /// [`command_timeout`](crate::workspace::WorkspaceBuilder::command_timeout) for this
+    /// command. Set to `None` to disable the timeout.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Override the workspace-wide
+    /// [`command_no_output_timeout`](crate::workspace::WorkspaceBuilder::command_no_output_timeout)
+    /// for this command. Set to `None` to disable the timeout.
+    pub fn no_output_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.no_output_timeout = timeout;
+        self
+    }
+
+    /// Bound how long this command is allowed to run by an overall [`Deadline`], on top of its
+    /// own timeout. See the [`Command`] docs for how the two combine.
+    pub fn deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Run the command to completion.
+    pub fn run(self) -> Result<(), Error> {
+        let command_timeout = self.timeout;
+        let effective_timeout = match self.deadline {
+            Some(deadline) => deadline.check_and_combine(command_timeout)?,
+            None => command_timeout,
+        };
+        let no_output_timeout = self.no_output_timeout;
+
+        let mut cmd = StdCommand::new(&self.program);
+        cmd.args(&self.args);
+        if let Some(cd) = &self.cd {
+            cmd.current_dir(cd);
+        }
+        for (key, value) in &self.envs {
+            cmd.env(key, value);
+        }
+        if self.workspace.offline() {
+            cmd.env("CARGO_NET_OFFLINE", "true");
+        }
+        if self.workspace.registry_protocol() == RegistryProtocol::Sparse {
+            cmd.env("CARGO_REGISTRIES_CRATES_IO_PROTOCOL", "sparse");
+            // `-Z`-gated unstable cargo features are only honored through env vars on
+            // stable-channel cargo when `RUSTC_BOOTSTRAP=1` is also set; without it, the
+            // pre-stabilization toolchains this is meant to support would silently ignore
+            // `CARGO_UNSTABLE_SPARSE_REGISTRY` and fall back to a full git clone.
+            cmd.env("CARGO_UNSTABLE_SPARSE_REGISTRY", "true");
+            cmd.env("RUSTC_BOOTSTRAP", "1");
+        }
+
+        let program = self.program.to_string_lossy().into_owned();
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|_| format!("failed to spawn `{}`", program))?;
+
+        let last_output = Arc::new(Mutex::new(Instant::now()));
+        let pumps = [
+            spawn_output_pump(child.stdout.take(), last_output.clone()),
+            spawn_output_pump(child.stderr.take(), last_output.clone()),
+        ];
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child
+                .wait_timeout(Duration::from_millis(500))
+                .with_context(|_| format!("failed to wait for `{}`", program))?
+            {
+                break status;
+            }
+            if let Some(timeout) = effective_timeout {
+                if start.elapsed() >= timeout {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(err_msg(format!(
+                        "command `{}` timed out after {:?}",
+                        program, timeout
+                    )));
+                }
+            }
+            if let Some(timeout) = no_output_timeout {
+                if last_output.lock().unwrap().elapsed() >= timeout {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(err_msg(format!(
+                        "command `{}` produced no output for {:?}, killing it",
+                        program, timeout
+                    )));
+                }
+            }
+        };
+
+        for pump in pumps {
+            let _ = pump.join();
+        }
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(err_msg(format!("command `{}` failed: {}", program, status)))
+        }
+    }
+}
+
+// Stream a child's stdout/stderr to the log line by line, recording when output was last seen
+// so `no_output_timeout` can be enforced from the wait loop in `Command::run`.
+fn spawn_output_pump(
+    stream: Option<impl Read + Send + 'static>,
+    last_output: Arc<Mutex<Instant>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let stream = match stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            info!("{}", line);
+            *last_output.lock().unwrap() = Instant::now();
+        }
+    })
+}
@@ -1,11 +1,11 @@
 use crate::build::BuildDirectory;
 use crate::cmd::{Command, SandboxImage};
 use crate::Toolchain;
-use failure::{Error, ResultExt};
+use failure::{err_msg, Error, ResultExt};
 use log::info;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[cfg(windows)]
 static DEFAULT_SANDBOX_IMAGE: &str = "rustops/crates-build-env-windows";
@@ -13,9 +13,80 @@ static DEFAULT_SANDBOX_IMAGE: &str = "rustops/crates-build-env-windows";
 #[cfg(not(windows))]
 static DEFAULT_SANDBOX_IMAGE: &str = "rustops/crates-build-env";
 
+static CRATES_IO_INDEX_URL: &str = "https://github.com/rust-lang/crates.io-index";
+
+// The directory name cargo uses on disk for the crates.io registry index, derived from hashing
+// its source URL. This value is stable across cargo versions.
+static CRATES_IO_INDEX_DIR: &str = "github.com-1ecc6299db9ec823";
+
 const DEFAULT_COMMAND_TIMEOUT: Option<Duration> = Some(Duration::from_secs(15 * 60));
 const DEFAULT_COMMAND_NO_OUTPUT_TIMEOUT: Option<Duration> = None;
 
+/// Which protocol the workspace uses to talk to the crates.io registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryProtocol {
+    /// Clone and maintain a full copy of the crates.io git index. This is the default, and the
+    /// only protocol compatible with
+    /// [`crates_io_index_revision`](struct.WorkspaceBuilder.html#method.crates_io_index_revision).
+    Git,
+    /// Use cargo's sparse HTTP protocol, which only downloads the index entries a build
+    /// actually needs instead of the whole index. This speeds up workspace initialization and
+    /// avoids the disk cost of a full index clone, which matters when running many workspaces
+    /// in parallel.
+    Sparse,
+}
+
+impl Default for RegistryProtocol {
+    fn default() -> Self {
+        RegistryProtocol::Git
+    }
+}
+
+/// An absolute point in time by which a whole sequence of commands must finish running.
+///
+/// A [`Command`](cmd/struct.Command.html)'s own timeout resets every time it's started, so a
+/// pipeline that runs several commands back to back (fetch, build, test, doc, ...) can overrun
+/// any intended total time budget even if each individual command stays within its timeout. A
+/// `Deadline` is computed once, up front, and can then be attached to every command in the
+/// pipeline with [`Command::deadline`](cmd/struct.Command.html#method.deadline): each one is
+/// given whatever time remains until the deadline, capped by its own timeout, and commands
+/// started after the deadline has passed fail immediately instead of running.
+#[derive(Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// Create a new deadline `budget` from now.
+    pub fn new(budget: Duration) -> Result<Self, Error> {
+        Instant::now()
+            .checked_add(budget)
+            .map(Deadline)
+            .ok_or_else(|| err_msg("the provided deadline is too far in the future to represent"))
+    }
+
+    /// Time left before the deadline is reached, or `None` if it already passed.
+    pub(crate) fn time_left(&self) -> Option<Duration> {
+        self.0.checked_duration_since(Instant::now())
+    }
+
+    /// Combine this deadline with a command-level timeout, returning the smaller of the two. An
+    /// error is returned if the deadline has already passed, since the caller shouldn't start a
+    /// new command in that case.
+    pub(crate) fn check_and_combine(
+        &self,
+        command_timeout: Option<Duration>,
+    ) -> Result<Option<Duration>, Error> {
+        match self.time_left() {
+            None => Err(err_msg(
+                "overall deadline exceeded, refusing to start a new command",
+            )),
+            Some(left) => Ok(Some(match command_timeout {
+                Some(timeout) => std::cmp::min(timeout, left),
+                None => left,
+            })),
+        }
+    }
+}
+
 /// Builder of a [`Workspace`](struct.Workspace.html).
 pub struct WorkspaceBuilder {
     user_agent: String,
@@ -24,6 +95,16 @@ pub struct WorkspaceBuilder {
     command_timeout: Option<Duration>,
     command_no_output_timeout: Option<Duration>,
     fast_init: bool,
+    crates_io_index_revision: Option<String>,
+    offline: bool,
+    registry_protocol: RegistryProtocol,
+    cargo_home: Option<PathBuf>,
+    rustup_home: Option<PathBuf>,
+    registry_cache: Option<PathBuf>,
+    http_proxy: Option<reqwest::Url>,
+    http_timeout: Option<Duration>,
+    root_certificates: Vec<reqwest::Certificate>,
+    accept_invalid_certs: bool,
 }
 
 impl WorkspaceBuilder {
@@ -39,6 +120,16 @@ impl WorkspaceBuilder {
             command_timeout: DEFAULT_COMMAND_TIMEOUT,
             command_no_output_timeout: DEFAULT_COMMAND_NO_OUTPUT_TIMEOUT,
             fast_init: false,
+            crates_io_index_revision: None,
+            offline: false,
+            registry_protocol: RegistryProtocol::default(),
+            cargo_home: None,
+            rustup_home: None,
+            registry_cache: None,
+            http_proxy: None,
+            http_timeout: None,
+            root_certificates: Vec::new(),
+            accept_invalid_certs: false,
         }
     }
 
@@ -84,6 +175,111 @@ impl WorkspaceBuilder {
         self
     }
 
+    /// Pin the crates.io registry index to a specific git revision instead of always fetching
+    /// the latest one.
+    ///
+    /// By default every call to [`Workspace::update_cratesio_registry`] advances the local
+    /// clone of the index to whatever `HEAD` happens to be upstream, so the same build run
+    /// today and next week can resolve different dependency versions. Setting a revision here
+    /// makes dependency resolution reproducible across runs: the index is checked out at
+    /// exactly this commit and never moved, so it can also be snapshotted and restored without
+    /// needing network access again. The revision is persisted in the workspace, so reopening
+    /// an existing workspace without calling this method again will keep reusing it.
+    ///
+    /// This only has an effect when using [`RegistryProtocol::Git`] (the default); it's ignored
+    /// when [`registry_protocol`](#method.registry_protocol) is set to
+    /// [`RegistryProtocol::Sparse`], since the sparse protocol has no local index to pin.
+    pub fn crates_io_index_revision(mut self, revision: &str) -> Self {
+        self.crates_io_index_revision = Some(revision.into());
+        self
+    }
+
+    /// Set the protocol used to talk to the crates.io registry (defaults to
+    /// [`RegistryProtocol::Git`]).
+    ///
+    /// Setting this to [`RegistryProtocol::Sparse`] configures
+    /// `CARGO_REGISTRIES_CRATES_IO_PROTOCOL=sparse` (plus the unstable flag needed on toolchains
+    /// that haven't stabilized it yet) on every [`Command`](cmd/struct.Command.html) the
+    /// workspace spawns, and skips the full-index git clone normally performed during
+    /// initialization. This is most useful for large fleets of automated workspaces, where the
+    /// full index clone otherwise dominates initialization time and disk usage.
+    pub fn registry_protocol(mut self, protocol: RegistryProtocol) -> Self {
+        self.registry_protocol = protocol;
+        self
+    }
+
+    /// Enable or disable offline mode (disabled by default).
+    ///
+    /// Once the registry index and the caches it depends on have been populated, enabling this
+    /// sets `CARGO_NET_OFFLINE=true` on every [`Command`](cmd/struct.Command.html) the workspace
+    /// spawns, so builds never attempt network access. This is most useful together with
+    /// [`crates_io_index_revision`](#method.crates_io_index_revision), which guarantees the
+    /// index is already in the state the build needs.
+    pub fn offline(mut self, enable: bool) -> Self {
+        self.offline = enable;
+        self
+    }
+
+    /// Override where the cargo home used by this workspace lives.
+    ///
+    /// By default the cargo home is a `cargo-home` directory inside the workspace's own path, so
+    /// every workspace maintains its own copy of the registry index and downloaded crate
+    /// sources. Pointing several workspaces at the same shared, read-mostly directory here lets
+    /// them reuse those downloads instead of each re-fetching them, which matters when running
+    /// many workspaces in parallel (e.g. a benchmark or CI fleet). `init` takes a file lock
+    /// around this directory so concurrent initializations sharing it don't race.
+    pub fn cargo_home(mut self, path: PathBuf) -> Self {
+        self.cargo_home = Some(path);
+        self
+    }
+
+    /// Same as [`cargo_home`](#method.cargo_home), but for the rustup home used by this
+    /// workspace.
+    pub fn rustup_home(mut self, path: PathBuf) -> Self {
+        self.rustup_home = Some(path);
+        self
+    }
+
+    /// Override where rustwide's own cache directory lives, so it can be shared between
+    /// workspaces the same way [`cargo_home`](#method.cargo_home) can. `init` takes a file lock
+    /// around this directory too, so concurrent initializations sharing it don't race.
+    pub fn registry_cache(mut self, path: PathBuf) -> Self {
+        self.registry_cache = Some(path);
+        self
+    }
+
+    /// Route the HTTP requests rustwide makes through its own HTTP client (such as crate
+    /// downloads) through an HTTP/HTTPS proxy. Useful behind a corporate proxy or an air-gapped
+    /// mirror. Commands spawned by the workspace, including the cargo and git commands that
+    /// fetch the registry index, are not configured to use this proxy.
+    pub fn http_proxy(mut self, proxy: reqwest::Url) -> Self {
+        self.http_proxy = Some(proxy);
+        self
+    }
+
+    /// Bound the time spent on any single HTTP request rustwide makes, independently of the
+    /// timeouts applied to spawned [`Command`](cmd/struct.Command.html)s.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Trust an additional root certificate when making HTTP requests, for example to reach an
+    /// internal crates mirror signed by a private CA.
+    pub fn add_root_certificate(mut self, cert: reqwest::Certificate) -> Self {
+        self.root_certificates.push(cert);
+        self
+    }
+
+    /// Disable TLS certificate validation for HTTP requests (disabled, i.e. validation stays
+    /// on, by default). Only useful when talking to a mirror with a self-signed certificate
+    /// that can't be trusted through [`add_root_certificate`](#method.add_root_certificate); it
+    /// removes protection against man-in-the-middle attacks, so use it with care.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
     /// Initialize the workspace. This will create all the necessary local files and fetch the rest from the network. It's
     /// not unexpected for this method to take minutes to run on slower network connections.
     pub fn init(self) -> Result<Workspace, Error> {
@@ -103,9 +299,20 @@ impl WorkspaceBuilder {
 
             let mut headers = reqwest::header::HeaderMap::new();
             headers.insert(reqwest::header::USER_AGENT, self.user_agent.parse()?);
-            let http = reqwest::ClientBuilder::new()
-                .default_headers(headers)
-                .build()?;
+            let mut http_builder = reqwest::ClientBuilder::new().default_headers(headers);
+            if let Some(proxy) = self.http_proxy {
+                http_builder = http_builder.proxy(reqwest::Proxy::all(proxy)?);
+            }
+            if let Some(timeout) = self.http_timeout {
+                http_builder = http_builder.timeout(timeout);
+            }
+            for cert in self.root_certificates {
+                http_builder = http_builder.add_root_certificate(cert);
+            }
+            if self.accept_invalid_certs {
+                http_builder = http_builder.danger_accept_invalid_certs(true);
+            }
+            let http = http_builder.build()?;
 
             let ws = Workspace {
                 inner: Arc::new(WorkspaceInner {
@@ -114,20 +321,59 @@ impl WorkspaceBuilder {
                     sandbox_image,
                     command_timeout: self.command_timeout,
                     command_no_output_timeout: self.command_no_output_timeout,
+                    crates_io_index_revision: self.crates_io_index_revision,
+                    offline: self.offline,
+                    registry_protocol: self.registry_protocol,
+                    cargo_home_override: self.cargo_home,
+                    rustup_home_override: self.rustup_home,
+                    registry_cache_override: self.registry_cache,
                 }),
             };
-            ws.init(self.fast_init)?;
+
+            // `cargo_home`, `rustup_home` and the cache dir can all be shared between several
+            // workspaces initializing concurrently, so take an extra lock scoped to each of
+            // those directories (on top of this workspace's own lock above) before touching them.
+            with_shared_lock(ws.cargo_home(), "initialize the shared cargo home", || {
+                with_shared_lock(
+                    ws.rustup_home(),
+                    "initialize the shared rustup home",
+                    || {
+                        with_shared_lock(
+                            ws.cache_dir(),
+                            "initialize the shared cache directory",
+                            || ws.init(self.fast_init),
+                        )
+                    },
+                )
+            })?;
+
             Ok(ws)
         })
     }
 }
 
+fn with_shared_lock<T>(
+    path: PathBuf,
+    reason: &str,
+    f: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    std::fs::create_dir_all(&path)
+        .with_context(|_| format!("failed to create directory: {}", path.display()))?;
+    crate::utils::file_lock(&path.join(".rustwide-lock"), reason, f)
+}
+
 struct WorkspaceInner {
     http: reqwest::Client,
     path: PathBuf,
     sandbox_image: SandboxImage,
     command_timeout: Option<Duration>,
     command_no_output_timeout: Option<Duration>,
+    crates_io_index_revision: Option<String>,
+    offline: bool,
+    registry_protocol: RegistryProtocol,
+    cargo_home_override: Option<PathBuf>,
+    rustup_home_override: Option<PathBuf>,
+    registry_cache_override: Option<PathBuf>,
 }
 
 /// Directory on the filesystem containing rustwide's state and caches.
@@ -183,15 +429,24 @@ impl Workspace {
     }
 
     pub(crate) fn cargo_home(&self) -> PathBuf {
-        self.inner.path.join("cargo-home")
+        self.inner
+            .cargo_home_override
+            .clone()
+            .unwrap_or_else(|| self.inner.path.join("cargo-home"))
     }
 
     pub(crate) fn rustup_home(&self) -> PathBuf {
-        self.inner.path.join("rustup-home")
+        self.inner
+            .rustup_home_override
+            .clone()
+            .unwrap_or_else(|| self.inner.path.join("rustup-home"))
     }
 
     pub(crate) fn cache_dir(&self) -> PathBuf {
-        self.inner.path.join("cache")
+        self.inner
+            .registry_cache_override
+            .clone()
+            .unwrap_or_else(|| self.inner.path.join("cache"))
     }
 
     pub(crate) fn builds_dir(&self) -> PathBuf {
@@ -210,6 +465,14 @@ impl Workspace {
         self.inner.command_no_output_timeout
     }
 
+    pub(crate) fn offline(&self) -> bool {
+        self.inner.offline
+    }
+
+    pub(crate) fn registry_protocol(&self) -> RegistryProtocol {
+        self.inner.registry_protocol
+    }
+
     fn init(&self, fast_init: bool) -> Result<(), Error> {
         info!("installing tools required by rustwide");
         crate::tools::install(self, fast_init)?;
@@ -219,6 +482,23 @@ impl Workspace {
     }
 
     fn update_cratesio_registry(&self) -> Result<(), Error> {
+        if self.inner.registry_protocol == RegistryProtocol::Sparse {
+            // The sparse protocol has no local index to maintain: cargo fetches index entries
+            // lazily over HTTP the first time a build actually needs them.
+            return Ok(());
+        }
+
+        if let Some(revision) = self.inner.crates_io_index_revision.clone() {
+            return self.pin_cratesio_registry(&revision);
+        }
+
+        if let Some(revision) = self.persisted_cratesio_index_revision()? {
+            // The workspace was pinned to a revision in a previous `init`, but the caller didn't
+            // pass `crates_io_index_revision` again this time: keep reusing the pinned index
+            // instead of silently falling back to tracking upstream.
+            return self.pin_cratesio_registry(&revision);
+        }
+
         // This nop cargo command is to update the registry so we don't have to do it for each
         // crate.  using `install` is a temporary solution until
         // https://github.com/rust-lang/cargo/pull/5961 is ready
@@ -231,4 +511,118 @@ impl Workspace {
         // ignore the error untill https://github.com/rust-lang/cargo/pull/5961 is ready
         Ok(())
     }
+
+    // Path to the crates.io index clone cargo expects inside this workspace's cargo home.
+    fn cratesio_index_dir(&self) -> PathBuf {
+        self.cargo_home()
+            .join("registry")
+            .join("index")
+            .join(CRATES_IO_INDEX_DIR)
+    }
+
+    // Read back the revision a previous call to `pin_cratesio_registry` left the index at, if
+    // any, so reopening a workspace keeps reusing it even without `crates_io_index_revision`
+    // being passed to the builder again.
+    fn persisted_cratesio_index_revision(&self) -> Result<Option<String>, Error> {
+        let pinned_revision_file = self.cratesio_index_dir().join(".rustwide-pinned-revision");
+        match std::fs::read_to_string(&pinned_revision_file) {
+            Ok(revision) => Ok(Some(revision)),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|_| {
+                format!(
+                    "failed to read the pinned crates.io index revision from {}",
+                    pinned_revision_file.display()
+                )
+            })?,
+        }
+    }
+
+    // Check out the crates.io index at exactly `revision`, so that dependency resolution is
+    // reproducible across workspace runs instead of always tracking the latest upstream commit.
+    fn pin_cratesio_registry(&self, revision: &str) -> Result<(), Error> {
+        let index = self.cratesio_index_dir();
+        let pinned_revision_file = index.join(".rustwide-pinned-revision");
+
+        if index.join(".git").is_dir() {
+            if std::fs::read_to_string(&pinned_revision_file)
+                .ok()
+                .as_deref()
+                == Some(revision)
+            {
+                // Already checked out at the requested revision, nothing to do.
+                return Ok(());
+            }
+            Command::new(self, "git")
+                .args(&["fetch", "origin", revision])
+                .cd(&index)
+                .run()
+                .with_context(|_| "failed to fetch the pinned crates.io index revision")?;
+        } else {
+            std::fs::create_dir_all(&index).with_context(|_| {
+                format!(
+                    "failed to create the registry index directory: {}",
+                    index.display()
+                )
+            })?;
+            Command::new(self, "git")
+                .args(&["clone", CRATES_IO_INDEX_URL, "."])
+                .cd(&index)
+                .run()
+                .with_context(|_| "failed to clone the crates.io index")?;
+        }
+
+        Command::new(self, "git")
+            .args(&["reset", "--hard", revision])
+            .cd(&index)
+            .run()
+            .with_context(|_| {
+                format!("failed to check out crates.io index revision {}", revision)
+            })?;
+
+        std::fs::write(&pinned_revision_file, revision)
+            .with_context(|_| "failed to persist the pinned crates.io index revision")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadline;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn deadline_already_passed_errors() {
+        let deadline = Deadline::new(Duration::from_millis(1)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(deadline.check_and_combine(None).is_err());
+        assert!(deadline.time_left().is_none());
+    }
+
+    #[test]
+    fn deadline_shorter_than_command_timeout_wins() {
+        let deadline = Deadline::new(Duration::from_secs(60)).unwrap();
+        let combined = deadline
+            .check_and_combine(Some(Duration::from_secs(3600)))
+            .unwrap()
+            .unwrap();
+        assert!(combined <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn command_timeout_shorter_than_deadline_wins() {
+        let deadline = Deadline::new(Duration::from_secs(3600)).unwrap();
+        let combined = deadline
+            .check_and_combine(Some(Duration::from_secs(5)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(combined, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn no_command_timeout_uses_remaining_deadline() {
+        let deadline = Deadline::new(Duration::from_secs(60)).unwrap();
+        let combined = deadline.check_and_combine(None).unwrap().unwrap();
+        assert!(combined <= Duration::from_secs(60));
+    }
 }